@@ -1,35 +1,106 @@
 mod stdlib;
 
 pub mod preprocessing {
-    use std::{io::{Error, ErrorKind}, collections::HashMap, fs};
+    use std::{io::{Error, ErrorKind, Write}, collections::{HashMap, HashSet}, fs, ops::Range, path::{Path, PathBuf}, process::{Command, Stdio}, rc::Rc};
 
     use crate::stdlib;
 
     /// # Definition
-    /// An RHL definiton is an optional string, since it can either be a code snippet or just an empty value.
-    /// This is used for the (albeit basic) macro/constant system.
-    type Definition = Option<String>;
+    /// An RHL definition is either object-like (a code snippet or an empty value, substituted
+    /// verbatim wherever its name appears) or function-like (an ordered parameter list plus a
+    /// body template, substituted only when its name is immediately followed by a call `(...)`).
+    /// This is used for the macro/constant system.
+    #[derive(Clone)]
+    pub enum Definition {
+        Object(Option<String>),
+        Function { params: Vec<String>, body: String }
+    }
 
     /// # RoseHipLang Preprocessor
     /// The RHL Preprocessor operates similar to the one in the C programming language.
     /// It runs over the source, line by line.
     /// Lines beginning with a '#' are designated as a preprocessor directive and parsed, then handled.
     /// In the event a preprocessor directive cannot be parsed, it is assumed to be invalid and an error is returned.
-    /// 
+    ///
     /// # Directives
     /// * `#ifdef <ident>` - Only outputs the code to the paired `#endif` or `#else` directive if IDENT is defined.
     /// * `#ifundef <ident>` - Only outputs the code to the paired `#endif` or `#else` directive if IDENT is ***not*** defined.
+    /// * `#if <expr>` - Only outputs the code to the paired `#elif`/`#else`/`#endif` if the constant expression EXPR is nonzero.
+    ///   Supports `defined(IDENT)`/`defined IDENT`, integer literals, `!`, `&& || == != < > <= >=`, `+ - * /` and parentheses.
+    /// * `#elif <expr>` - Chains onto an `#if`/`#ifdef`/`#ifundef`, evaluated only if no earlier branch in the chain was taken.
     /// * `#endif` - The counterpart to `#ifdef <ident>`
     /// * `#else` - Can be placed inside of an `#ifdef` pair to output the code after it only if IDENT is not defined.
     /// * `#with <$ident / "path">` - Cuts & pastes the code from either the stdlib module $IDENT or the file at PATH.
+    ///   PATH is resolved relative to the directory of the file containing the directive. Including a file that is
+    ///   already on the include chain is an error (circular include); a file already fully processed elsewhere in
+    ///   the chain is skipped (include-once).
     /// * `#define <ident>` - Defines the identifier IDENT without a value.
     /// * `#define <ident> <...src>` - Defines the identifier IDENT with the value of all the code after it.
+    /// * `#define <ident>(<params...>) <...src>` - Defines a function-like macro. Each call `IDENT(args...)` found later
+    ///   in the source is replaced by the body with every parameter swapped for its matching argument.
     /// * `#undefine <ident>` - Un-defines an identifier, regardless of whether or not it has a value associated with it.
+    /// * `#plugin <name> ... #endplugin` - Pipes the enclosed block, together with the current defines, as JSON to the
+    ///   external command registered under NAME via [`Preprocessor::register_plugin`], and splices its stdout back
+    ///   in place of the block. A non-zero plugin exit code is surfaced as an `Error`.
+    ///
+    /// # Source spans
+    /// Alongside `out`, a [`Preprocessor`] keeps a parallel map of [`SourceSpan`]s recording which
+    /// original file and line each byte of `out` came from, surviving `#with` includes and macro
+    /// expansion. [`Preprocessor::map_offset`] resolves a byte offset in `out` back to that
+    /// original location, for use by downstream lexer/parser diagnostics.
     pub struct Preprocessor<'a> {
         lines : Vec<&'a str>,
         out : String,
         defs : HashMap<String, Definition>,
-        index : usize
+        index : usize,
+        cond_stack : Vec<ConditionalFrame>,
+        /// Directory `#with "path"` is resolved against. `None` when this `Preprocessor` was
+        /// built via [`Preprocessor::new`] directly from a string rather than a file (use
+        /// [`Preprocessor::from_path`] to set this for the root file too), in which case paths
+        /// fall back to resolving against the process's current directory, as before.
+        base_dir : Option<PathBuf>,
+        /// Canonicalized paths of the files currently being included, innermost last. Used to
+        /// detect `#with` cycles.
+        include_stack : Vec<PathBuf>,
+        /// Canonicalized paths of files that have already been fully processed somewhere in
+        /// this include chain, so a diamond-shaped `#with` graph doesn't duplicate their code.
+        included : HashSet<PathBuf>,
+        /// Registered `#plugin` commands, keyed by the name used in the directive.
+        plugins : HashMap<String, String>,
+        /// The file this `Preprocessor`'s input came from, for attribution in `spans`. A
+        /// synthetic name (`<input>`, `<stdlib:$ident>`) when there is no real file on disk.
+        file : Rc<PathBuf>,
+        /// Parallel map from ranges of `out` to the original file/line they were produced from.
+        /// See [`Preprocessor::map_offset`].
+        spans : Vec<SourceSpan>
+    }
+
+    /// Records that `out[out_range]` was produced from line `original_line` of `file`. Pushed
+    /// to `Preprocessor::spans` as output is emitted, so a byte offset in the fully-expanded
+    /// output can still be traced back to where it came from in the original source.
+    #[derive(Clone)]
+    pub struct SourceSpan {
+        pub out_range : Range<usize>,
+        pub file : Rc<PathBuf>,
+        pub original_line : usize
+    }
+
+    /// One open `#if`/`#ifdef`/`#ifundef` ... `#endif` construct on the conditional stack.
+    /// The frame persists across its whole `#elif`/`#else` chain; only its fields change as
+    /// each new segment of the chain is entered.
+    struct ConditionalFrame {
+        /// Whether the enclosing scope was active when this frame was pushed. Frozen for the
+        /// frame's whole lifetime, since the stack discipline guarantees a parent frame can't
+        /// change state while this frame is still open.
+        parent_active : bool,
+        /// Whether some segment of this chain has already matched, so later `#elif`/`#else`
+        /// segments are skipped even if their own condition would otherwise hold.
+        branch_taken : bool,
+        /// Whether an `#else` has already been seen in this frame, to catch a stray second one.
+        else_seen : bool,
+        /// Whether the *current* segment (the lines between the last directive in this chain
+        /// and the next) should be emitted.
+        segment_active : bool
     }
     
     impl std::fmt::Display for Preprocessor<'_> {
@@ -47,30 +118,60 @@ pub mod preprocessing {
 
     impl Preprocessor<'_> {
         pub fn new<'a>(input: &'a str) -> Preprocessor<'a> {
-            Preprocessor { 
+            Preprocessor {
                 lines: input.lines().collect::<Vec<&'a str>>(),
                 out: String::new(),
                 defs: HashMap::new(),
-                index: 0
+                index: 0,
+                cond_stack: Vec::new(),
+                base_dir: None,
+                include_stack: Vec::new(),
+                included: HashSet::new(),
+                plugins: HashMap::new(),
+                file: Rc::new(PathBuf::from("<input>")),
+                spans: Vec::new()
             }
         }
 
+        /// Builds a `Preprocessor` for `input`, which was read from `path`. Unlike
+        /// [`Preprocessor::new`], this resolves `base_dir` and `file` against `path`, so a
+        /// top-level `#with "relative/path"` directive resolves relative to `path`'s directory
+        /// the same way a nested include's `#with` already does, and is also pushed onto
+        /// `include_stack` so a file that `#with`s itself (directly or via a cycle) is caught as
+        /// a circular include rather than recursing forever.
+        pub fn from_path<'a>(path: &Path, input: &'a str) -> Result<Preprocessor<'a>, Error> {
+            let canonical = fs::canonicalize(path)?;
+
+            let mut p = Preprocessor::new(input);
+            p.base_dir = canonical.parent().map(|dir| dir.to_path_buf());
+            p.include_stack.push(canonical.clone());
+            p.file = Rc::new(canonical);
+
+            Ok(p)
+        }
+
         pub fn define(&mut self, key : String, value : Definition) {
             self.defs.insert(key, value);
         }
 
+        /// Registers the external command invoked by `#plugin <name> ... #endplugin` blocks
+        /// naming NAME. Re-registering a name replaces its command.
+        pub fn register_plugin(&mut self, name : String, command : String) {
+            self.plugins.insert(name, command);
+        }
+
         pub fn run<'a>(&'a mut self) -> Result<&'a str, Error> {
             while self.index < self.lines.len() {
                 let mut line = self.lines[self.index].to_owned();
 
-                if !line.starts_with("#") { 
-                    let mut iter = self.defs.iter();
-                    while let Some(val) = iter.next() {
-                        if let Some(v) = val.1 {
-                            line = line.replace(val.0, v);
-                        }
+                if !line.starts_with("#") {
+                    if self.stack_active() {
+                        let mut expanding = HashSet::new();
+                        line = self.expand_line(&line, &mut expanding)?;
+                        let start = self.out.len();
+                        self.out.push_str(&line);
+                        self.push_span(start, self.index);
                     }
-                    self.out.push_str(&line);
                     self.index += 1;
                     continue;
                 }
@@ -89,25 +190,48 @@ pub mod preprocessing {
 
                 match first_word {
                     "#define" => {
-                        let ident = match words.next() {
-                            Some(val) => val.to_owned(),
-                            None => return Err(Error::new(ErrorKind::InvalidData, format!("Failed to get <IDENT> in preprocessor directive '{line}' at line {}.", self.index)))
-                        };
+                        if self.stack_active() {
+                            let rest = line["#define".len()..].trim_start();
 
-                        let expr = match words.next() {
-                            Some(val) => Some(val.to_owned()),
-                            None => None
-                        };
+                            if rest.is_empty() {
+                                return Err(Error::new(ErrorKind::InvalidData, format!("Failed to get <IDENT> in preprocessor directive '{line}' at line {}.", self.index)));
+                            }
+
+                            let name_end = rest.find(|c: char| c == '(' || c.is_whitespace()).unwrap_or(rest.len());
+                            let ident = rest[..name_end].to_owned();
+
+                            if rest[name_end..].starts_with('(') {
+                                let close = match rest[name_end..].find(')') {
+                                    Some(idx) => name_end + idx,
+                                    None => return Err(Error::new(ErrorKind::InvalidData, format!("Unterminated parameter list in `#define` directive '{line}' at line {}.", self.index)))
+                                };
+
+                                let params : Vec<String> = rest[name_end + 1..close]
+                                    .split(',')
+                                    .map(|p| p.trim().to_owned())
+                                    .filter(|p| !p.is_empty())
+                                    .collect();
+
+                                let body = rest[close + 1..].trim().to_owned();
 
-                        self.defs.insert(ident, expr);
+                                self.defs.insert(ident, Definition::Function { params, body });
+                            } else {
+                                let value = rest[name_end..].trim();
+                                let expr = if value.is_empty() { None } else { Some(value.to_owned()) };
+
+                                self.defs.insert(ident, Definition::Object(expr));
+                            }
+                        }
                     },
                     "#undefine" => {
-                        let ident = match words.next() {
-                            Some(v) => v,
-                            None => return Err(Error::new(ErrorKind::InvalidData, format!("Error in preprocessor directive `#undefine <ident>` - failed to get identifier at line {}.", self.index)))
-                        };
+                        if self.stack_active() {
+                            let ident = match words.next() {
+                                Some(v) => v,
+                                None => return Err(Error::new(ErrorKind::InvalidData, format!("Error in preprocessor directive `#undefine <ident>` - failed to get identifier at line {}.", self.index)))
+                            };
 
-                        self.defs.remove(ident);
+                            self.defs.remove(ident);
+                        }
                     },
                     "#ifdef" => {
                         let ident = match words.next() {
@@ -116,12 +240,7 @@ pub mod preprocessing {
                         };
 
                         let defined = self.defs.contains_key(ident);
-
-                        self.index += 1;
-
-                        if defined {
-                            self.read_until_endif_or_else()?;
-                        }
+                        self.push_conditional(defined);
                     },
                     "#ifundef" => {
                         let ident = match words.next() {
@@ -130,36 +249,122 @@ pub mod preprocessing {
                         };
 
                         let defined = self.defs.contains_key(ident);
+                        self.push_conditional(!defined);
+                    },
+                    "#if" => {
+                        let cond = if self.stack_active() {
+                            let expr = line["#if".len()..].trim();
+                            ExprEvaluator::eval(&self.defs, expr)? != 0
+                        } else {
+                            false
+                        };
 
-                        self.index += 1;
-
-                        if !defined {
-                            self.read_until_endif_or_else()?;
-                        }
+                        self.push_conditional(cond);
                     },
-                    "#else" => self.read_until_endif_or_else()?,
-                    "#endif" => {
-                        return Err(Error::new(ErrorKind::InvalidData, format!("Unexpected #endif directive at line {}.", self.index)));
+                    "#elif" => {
+                        let expr = line["#elif".len()..].trim();
+                        self.handle_elif(expr)?;
                     },
+                    "#else" => self.handle_else()?,
+                    "#endif" => self.pop_conditional()?,
                     "#with" => {
-                        let second_word = match words.next() {
-                            Some(val) => val,
-                            None => return Err(Error::new(ErrorKind::InvalidData, format!("Expected file path or library name after #with directive at line {}", self.index)))
-                        };
+                        if self.stack_active() {
+                            let second_word = match words.next() {
+                                Some(val) => val,
+                                None => return Err(Error::new(ErrorKind::InvalidData, format!("Expected file path or library name after #with directive at line {}", self.index)))
+                            };
+
+                            if second_word.starts_with("$") {
+                                let src = match stdlib::BUILTIN_LIBS.get(second_word) {
+                                    Some(src) => src.to_owned(),
+                                    None => return Err(Error::new(ErrorKind::InvalidData, format!("No stdlib module with identifier {second_word} at line {}.", self.index)))
+                                };
 
-                        let src : String = if second_word.starts_with("$") {
-                            match stdlib::BUILTIN_LIBS.get(second_word) {
-                                Some(src) => src.to_owned(),
-                                None => return Err(Error::new(ErrorKind::InvalidData, format!("No stdlib module with identifier {second_word} at line {}.", self.index)))
+                                let mut p = Preprocessor::new(&src);
+                                p.defs = self.defs.clone();
+                                p.plugins = self.plugins.clone();
+                                p.file = Rc::new(PathBuf::from(format!("<stdlib:{second_word}>")));
+
+                                let base = self.out.len();
+                                self.out.push_str(&p.run()?);
+                                self.splice_spans(base, &p.spans);
+                                self.defs = p.defs;
+                            } else {
+                                // We've already parsed the directive before now, we'll be fine.
+                                let path = line.split_once(" ").unwrap().1;
+                                let requested = PathBuf::from(path);
+
+                                let resolved = match &self.base_dir {
+                                    Some(dir) => dir.join(&requested),
+                                    None => requested
+                                };
+
+                                let canonical = fs::canonicalize(&resolved)?;
+
+                                if self.include_stack.contains(&canonical) {
+                                    return Err(Error::new(ErrorKind::InvalidData, format!("Circular #with include of '{}' at line {} (include chain: {:?}).", canonical.display(), self.index, self.include_stack)));
+                                }
+
+                                if self.included.insert(canonical.clone()) {
+                                    let src = fs::read_to_string(&canonical)?;
+
+                                    let mut p = Preprocessor::new(&src);
+                                    p.defs = self.defs.clone();
+                                    p.plugins = self.plugins.clone();
+                                    p.base_dir = canonical.parent().map(|dir| dir.to_path_buf());
+                                    p.file = Rc::new(canonical.clone());
+                                    p.include_stack = self.include_stack.clone();
+                                    p.include_stack.push(canonical);
+                                    p.included = self.included.clone();
+
+                                    let expanded = p.run().map(|out| out.to_owned())?;
+
+                                    let base = self.out.len();
+                                    self.out.push_str(&expanded);
+                                    self.splice_spans(base, &p.spans);
+                                    self.defs = p.defs;
+                                    self.included = p.included;
+                                }
+                            }
+                        }
+                    },
+                    "#plugin" => {
+                        // The name is only required once we know this block will actually run;
+                        // a malformed `#plugin` inside a dead branch shouldn't error, matching
+                        // how `#define`/`#undefine`/`#with` are gated. We still have to find the
+                        // matching `#endplugin` regardless, so the rest of the source doesn't get
+                        // misparsed as directives.
+                        let name = words.next().map(|val| val.to_owned());
+
+                        let directive_line = self.index;
+                        self.index += 1;
+
+                        let mut block = Vec::new();
+
+                        loop {
+                            if self.index >= self.lines.len() {
+                                return Err(Error::new(ErrorKind::UnexpectedEof, format!("Expected `#endplugin` to close `#plugin` opened at line {directive_line}.")));
+                            }
+
+                            if self.lines[self.index].trim() == "#endplugin" {
+                                break;
                             }
-                        } else {
-                            // We've already parsed the directive before now, we'll be fine.
-                            let path = line.split_once(" ").unwrap();
-                            fs::read_to_string(path.1)?
-                        };
 
-                        let mut p = Preprocessor::new(&src);
-                        self.out.push_str(&p.run()?);
+                            block.push(self.lines[self.index]);
+                            self.index += 1;
+                        }
+
+                        if self.stack_active() {
+                            let name = match name {
+                                Some(name) => name,
+                                None => return Err(Error::new(ErrorKind::InvalidData, format!("Expected a plugin name after #plugin directive at line {directive_line}.")))
+                            };
+
+                            let transformed = self.run_plugin(&name, &block.join("\n"))?;
+                            let start = self.out.len();
+                            self.out.push_str(&transformed);
+                            self.push_span(start, directive_line);
+                        }
                     },
                     _ => return Err(Error::new(ErrorKind::InvalidData, format!("Invalid preprocessor directive `{first_word}` at line {}.", self.index)))
                 }
@@ -170,42 +375,754 @@ pub mod preprocessing {
             Ok(&self.out)
         }
 
-        fn read_until_endif_or_else(&mut self) -> Result<(), Error> {
-            let mut height: u16 = 0; 
+        /// Runs the block enclosed by `#plugin <name> ... #endplugin`, spawning the command
+        /// registered under `name` and feeding it `{ "source": "...", "defines": { ... } }` on
+        /// stdin. Returns the plugin's stdout, which is spliced into `out` in place of the block.
+        ///
+        /// The payload is written to stdin on a separate thread while this thread reads stdout,
+        /// rather than writing the whole payload before reading anything back: a plugin that
+        /// produces more than a pipe buffer's worth of output before it finishes consuming stdin
+        /// would otherwise deadlock both sides.
+        fn run_plugin(&self, name: &str, source: &str) -> Result<String, Error> {
+            let command = match self.plugins.get(name) {
+                Some(command) => command.clone(),
+                None => return Err(Error::new(ErrorKind::InvalidData, format!("No plugin registered with name '{name}' at line {}.", self.index)))
+            };
 
-            while self.index < self.lines.len() {
-                if !self.lines[self.index].starts_with("#") {
+            let payload = format!("{{\"source\":{},\"defines\":{}}}", Self::json_string(source), self.defs_to_json());
+
+            let mut child = Command::new(&command)
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .spawn()?;
+
+            let mut stdin = child.stdin.take().unwrap();
+            let writer = std::thread::spawn(move || stdin.write_all(payload.as_bytes()));
+
+            let output = child.wait_with_output()?;
+
+            match writer.join() {
+                Ok(result) => result?,
+                Err(_) => return Err(Error::other(format!("Plugin '{name}' stdin writer thread panicked at line {}.", self.index)))
+            }
+
+            if !output.status.success() {
+                return Err(Error::other(format!("Plugin '{name}' exited with status {:?} at line {}.", output.status.code(), self.index)));
+            }
+
+            String::from_utf8(output.stdout).map_err(|e| Error::new(ErrorKind::InvalidData, format!("Plugin '{name}' produced non-UTF8 output at line {}: {e}", self.index)))
+        }
+
+        /// Serializes `self.defs` as a JSON object for the `#plugin` wire format. Object-like
+        /// macros become their string value (or `null` if unset); function-like macros become
+        /// `{ "params": [...], "body": "..." }`.
+        fn defs_to_json(&self) -> String {
+            let fields : Vec<String> = self.defs.iter().map(|(name, def)| {
+                let value = match def {
+                    Definition::Object(Some(value)) => Self::json_string(value),
+                    Definition::Object(None) => "null".to_owned(),
+                    Definition::Function { params, body } => {
+                        let params = params.iter().map(|p| Self::json_string(p)).collect::<Vec<String>>().join(",");
+                        format!("{{\"params\":[{params}],\"body\":{}}}", Self::json_string(body))
+                    }
+                };
+
+                format!("{}:{value}", Self::json_string(name))
+            }).collect();
+
+            format!("{{{}}}", fields.join(","))
+        }
+
+        /// Escapes `s` as a JSON string literal, quotes included.
+        fn json_string(s: &str) -> String {
+            let mut out = String::from("\"");
+
+            for c in s.chars() {
+                match c {
+                    '"' => out.push_str("\\\""),
+                    '\\' => out.push_str("\\\\"),
+                    '\n' => out.push_str("\\n"),
+                    '\r' => out.push_str("\\r"),
+                    '\t' => out.push_str("\\t"),
+                    c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+                    c => out.push(c)
+                }
+            }
+
+            out.push('"');
+            out
+        }
+
+        /// Expands object-like substitutions, then any function-like macro calls, on a single
+        /// line of source. `expanding` tracks macro names currently being expanded on the call
+        /// stack so a macro that (directly or indirectly) invokes itself is left untouched
+        /// instead of recursing forever.
+        fn expand_line(&self, line: &str, expanding: &mut HashSet<String>) -> Result<String, Error> {
+            let mut expanded = line.to_owned();
+
+            for (name, def) in self.defs.iter() {
+                if let Definition::Object(Some(value)) = def {
+                    expanded = expanded.replace(name, value);
+                }
+            }
+
+            let chars : Vec<char> = expanded.chars().collect();
+            let mut result = String::new();
+            let mut i = 0;
+
+            while i < chars.len() {
+                if !(chars[i].is_alphabetic() || chars[i] == '_') {
+                    result.push(chars[i]);
+                    i += 1;
                     continue;
                 }
 
-                let directive : &str = match self.lines[self.index].split_once(" ") {
-                    Some(x) => x.0,
-                    None => self.lines[self.index]
-                };
+                let start = i;
+                while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                    i += 1;
+                }
+                let name : String = chars[start..i].iter().collect();
 
-                match directive {
-                    "#ifdef" => height += 1,
-                    "#ifundef" => height += 1,
-                    "#endif" => {
-                        height -= 1;
-                        if height <= 0 {
-                            self.index += 1;
-                            return Ok(());
+                if i < chars.len() && chars[i] == '(' && !expanding.contains(&name) {
+                    if let Some(Definition::Function { params, body }) = self.defs.get(&name) {
+                        let (args, end) = Self::parse_macro_args(&chars, i, self.index)?;
+
+                        if args.len() == params.len() {
+                            let substituted = Self::substitute_params(body, params, &args);
+
+                            expanding.insert(name.clone());
+                            let rescanned = self.expand_line(&substituted, expanding)?;
+                            expanding.remove(&name);
+
+                            result.push_str(&rescanned);
+                            i = end;
+                            continue;
+                        }
+                    }
+                }
+
+                result.push_str(&name);
+            }
+
+            Ok(result)
+        }
+
+        /// Substitutes every occurrence of a parameter name in `body` with its matching argument,
+        /// matching whole identifiers in a single left-to-right pass (the same way `expand_line`
+        /// walks a line) rather than one sequential `.replace()` per parameter. This keeps the
+        /// substitution hygienic: a parameter name that merely occurs as a substring of unrelated
+        /// text is left alone, and one parameter's substituted argument text can never be
+        /// re-matched and clobbered by a later parameter's replace.
+        fn substitute_params(body: &str, params: &[String], args: &[String]) -> String {
+            let map : HashMap<&str, &str> = params.iter().map(|p| p.as_str()).zip(args.iter().map(|a| a.as_str())).collect();
+
+            let chars : Vec<char> = body.chars().collect();
+            let mut result = String::new();
+            let mut i = 0;
+
+            while i < chars.len() {
+                if !(chars[i].is_alphabetic() || chars[i] == '_') {
+                    result.push(chars[i]);
+                    i += 1;
+                    continue;
+                }
+
+                let start = i;
+                while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                    i += 1;
+                }
+                let name : String = chars[start..i].iter().collect();
+
+                match map.get(name.as_str()) {
+                    Some(arg) => result.push_str(arg),
+                    None => result.push_str(&name)
+                }
+            }
+
+            result
+        }
+
+        /// Parses the `(arg, arg, ...)` call following a function-like macro name, starting at
+        /// the index of the opening `(`. Tracks paren nesting depth so commas inside nested
+        /// parentheses are not mistaken for argument separators. Returns the trimmed arguments
+        /// and the index just past the closing `)`.
+        fn parse_macro_args(chars: &[char], open_paren_index: usize, line_no: usize) -> Result<(Vec<String>, usize), Error> {
+            let mut depth = 0u32;
+            let mut args = Vec::new();
+            let mut current = String::new();
+            let mut i = open_paren_index;
+
+            loop {
+                if i >= chars.len() {
+                    return Err(Error::new(ErrorKind::InvalidData, format!("Unterminated macro argument list at line {line_no}.")));
+                }
+
+                match chars[i] {
+                    '(' => {
+                        depth += 1;
+                        if depth > 1 {
+                            current.push('(');
                         }
                     },
-                    "#else" => {
-                        if height - 1 <= 0 {
-                            self.index += 1;
-                            return Ok(());
+                    ')' => {
+                        depth -= 1;
+                        if depth == 0 {
+                            if !(args.is_empty() && current.trim().is_empty()) {
+                                args.push(current.trim().to_owned());
+                            }
+                            i += 1;
+                            break;
                         }
+                        current.push(')');
+                    },
+                    ',' if depth == 1 => {
+                        args.push(current.trim().to_owned());
+                        current.clear();
+                    },
+                    c => current.push(c)
+                }
+
+                i += 1;
+            }
+
+            Ok((args, i))
+        }
+
+        /// Whether every frame on the conditional stack is currently active, i.e. whether a
+        /// plain source line reached right now should be emitted.
+        fn stack_active(&self) -> bool {
+            self.cond_stack.iter().all(|frame| frame.segment_active)
+        }
+
+        /// Pushes a new frame for an `#if`/`#ifdef`/`#ifundef` directive whose condition just
+        /// evaluated to `condition`.
+        fn push_conditional(&mut self, condition: bool) {
+            let parent_active = self.stack_active();
+
+            self.cond_stack.push(ConditionalFrame {
+                parent_active,
+                branch_taken: condition,
+                else_seen: false,
+                segment_active: parent_active && condition
+            });
+        }
+
+        /// Handles an `#elif <expr>` directive against the innermost open frame.
+        fn handle_elif(&mut self, expr: &str) -> Result<(), Error> {
+            let (else_seen, branch_taken, parent_active) = match self.cond_stack.last() {
+                Some(frame) => (frame.else_seen, frame.branch_taken, frame.parent_active),
+                None => return Err(Error::new(ErrorKind::InvalidData, format!("Unexpected #elif directive with no matching #if at line {}.", self.index)))
+            };
+
+            if else_seen {
+                return Err(Error::new(ErrorKind::InvalidData, format!("Unexpected #elif directive after #else at line {}.", self.index)));
+            }
+
+            if branch_taken {
+                self.cond_stack.last_mut().unwrap().segment_active = false;
+                return Ok(());
+            }
+
+            let cond = if parent_active {
+                ExprEvaluator::eval(&self.defs, expr)? != 0
+            } else {
+                false
+            };
+            let frame = self.cond_stack.last_mut().unwrap();
+            frame.branch_taken = cond;
+            frame.segment_active = parent_active && cond;
+
+            Ok(())
+        }
+
+        /// Handles an `#else` directive against the innermost open frame.
+        fn handle_else(&mut self) -> Result<(), Error> {
+            let frame = match self.cond_stack.last_mut() {
+                Some(frame) => frame,
+                None => return Err(Error::new(ErrorKind::InvalidData, format!("Unexpected #else directive with no matching #if at line {}.", self.index)))
+            };
+
+            if frame.else_seen {
+                return Err(Error::new(ErrorKind::InvalidData, format!("Unexpected second #else directive at line {}.", self.index)));
+            }
+
+            frame.else_seen = true;
+
+            if frame.branch_taken {
+                frame.segment_active = false;
+            } else {
+                frame.branch_taken = true;
+                frame.segment_active = frame.parent_active;
+            }
+
+            Ok(())
+        }
+
+        /// Handles an `#endif` directive by popping the innermost open frame.
+        fn pop_conditional(&mut self) -> Result<(), Error> {
+            match self.cond_stack.pop() {
+                Some(_) => Ok(()),
+                None => Err(Error::new(ErrorKind::InvalidData, format!("Unexpected #endif directive with no matching #if at line {}.", self.index)))
+            }
+        }
+
+        /// Records that `self.out[start..self.out.len()]` came from `original_line` of this
+        /// `Preprocessor`'s `file`. A no-op for an empty range, since there's nothing to map.
+        fn push_span(&mut self, start: usize, original_line: usize) {
+            if start == self.out.len() {
+                return;
+            }
+
+            self.spans.push(SourceSpan {
+                out_range: start..self.out.len(),
+                file: self.file.clone(),
+                original_line
+            });
+        }
+
+        /// Merges a child `Preprocessor`'s `spans` into `self.spans`, shifting each `out_range`
+        /// by `base` (the length of `self.out` before the child's output was appended), since
+        /// the child's spans are relative to its own `out`.
+        fn splice_spans(&mut self, base: usize, child_spans: &[SourceSpan]) {
+            self.spans.extend(child_spans.iter().map(|span| SourceSpan {
+                out_range: (span.out_range.start + base)..(span.out_range.end + base),
+                file: span.file.clone(),
+                original_line: span.original_line
+            }));
+        }
+
+        /// The span map recorded so far. See [`Preprocessor::map_offset`].
+        pub fn spans(&self) -> &[SourceSpan] {
+            &self.spans
+        }
+
+        /// Resolves a byte offset into `out` (as returned by [`Preprocessor::run`]) back to the
+        /// original file and line it was produced from, or `None` if `byte` falls outside every
+        /// recorded span. Every non-empty write to `out` records a span, so this only happens
+        /// for an offset at or past the end of `out`.
+        pub fn map_offset(&self, byte: usize) -> Option<(&Path, usize)> {
+            self.spans.iter()
+                .find(|span| span.out_range.contains(&byte))
+                .map(|span| (span.file.as_path(), span.original_line))
+        }
+    }
+
+    /// Recursive-descent evaluator for the constant expressions accepted by `#if`/`#elif`.
+    /// Understands `defined(IDENT)` / `defined IDENT`, integer literals, identifiers (resolved
+    /// against the active `#define`s, with undefined names parsing to 0, as in C), unary `!`,
+    /// the binary operators `&& || == != < > <= >=` and `+ - * /`, and parenthesised grouping.
+    /// A nonzero result means "true" for branch selection.
+    struct ExprEvaluator<'a> {
+        tokens: Vec<String>,
+        pos: usize,
+        defs: &'a HashMap<String, Definition>
+    }
+
+    impl<'a> ExprEvaluator<'a> {
+        fn eval(defs: &'a HashMap<String, Definition>, src: &str) -> Result<i64, Error> {
+            let tokens = Self::tokenize(src);
+            let mut evaluator = ExprEvaluator { tokens, pos: 0, defs };
+
+            let value = evaluator.parse_or()?;
+
+            if evaluator.pos != evaluator.tokens.len() {
+                return Err(Error::new(ErrorKind::InvalidData, format!("Unexpected token `{}` in preprocessor expression `{src}`.", evaluator.tokens[evaluator.pos])));
+            }
+
+            Ok(value)
+        }
+
+        fn tokenize(src: &str) -> Vec<String> {
+            let chars : Vec<char> = src.chars().collect();
+            let mut tokens = Vec::new();
+            let mut i = 0;
+
+            while i < chars.len() {
+                let c = chars[i];
+
+                if c.is_whitespace() {
+                    i += 1;
+                    continue;
+                }
+
+                if c.is_alphabetic() || c == '_' {
+                    let start = i;
+                    while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                        i += 1;
                     }
-                    _ => continue
+                    tokens.push(chars[start..i].iter().collect());
+                    continue;
                 }
 
-                self.index += 1;
+                if c.is_ascii_digit() {
+                    let start = i;
+                    while i < chars.len() && chars[i].is_ascii_digit() {
+                        i += 1;
+                    }
+                    tokens.push(chars[start..i].iter().collect());
+                    continue;
+                }
+
+                let two : String = chars[i..(i + 2).min(chars.len())].iter().collect();
+                if matches!(two.as_str(), "&&" | "||" | "==" | "!=" | "<=" | ">=") {
+                    tokens.push(two);
+                    i += 2;
+                    continue;
+                }
+
+                if "()!<>+-*/".contains(c) {
+                    tokens.push(c.to_string());
+                    i += 1;
+                    continue;
+                }
+
+                i += 1;
+            }
+
+            tokens
+        }
+
+        fn peek(&self) -> Option<&str> {
+            self.tokens.get(self.pos).map(|s| s.as_str())
+        }
+
+        fn advance(&mut self) -> Option<String> {
+            let tok = self.tokens.get(self.pos).cloned();
+            if tok.is_some() {
+                self.pos += 1;
+            }
+            tok
+        }
+
+        fn expect(&mut self, expected: &str) -> Result<(), Error> {
+            match self.advance() {
+                Some(ref tok) if tok == expected => Ok(()),
+                Some(tok) => Err(Error::new(ErrorKind::InvalidData, format!("Expected `{expected}` but found `{tok}` in preprocessor expression."))),
+                None => Err(Error::new(ErrorKind::InvalidData, format!("Expected `{expected}` but found end of preprocessor expression.")))
+            }
+        }
+
+        fn parse_or(&mut self) -> Result<i64, Error> {
+            let mut lhs = self.parse_and()?;
+
+            while self.peek() == Some("||") {
+                self.advance();
+                let rhs = self.parse_and()?;
+                lhs = ((lhs != 0) || (rhs != 0)) as i64;
+            }
+
+            Ok(lhs)
+        }
+
+        fn parse_and(&mut self) -> Result<i64, Error> {
+            let mut lhs = self.parse_equality()?;
+
+            while self.peek() == Some("&&") {
+                self.advance();
+                let rhs = self.parse_equality()?;
+                lhs = ((lhs != 0) && (rhs != 0)) as i64;
+            }
+
+            Ok(lhs)
+        }
+
+        fn parse_equality(&mut self) -> Result<i64, Error> {
+            let mut lhs = self.parse_relational()?;
+
+            loop {
+                match self.peek() {
+                    Some("==") => { self.advance(); let rhs = self.parse_relational()?; lhs = (lhs == rhs) as i64; },
+                    Some("!=") => { self.advance(); let rhs = self.parse_relational()?; lhs = (lhs != rhs) as i64; },
+                    _ => break
+                }
+            }
+
+            Ok(lhs)
+        }
+
+        fn parse_relational(&mut self) -> Result<i64, Error> {
+            let mut lhs = self.parse_additive()?;
+
+            loop {
+                match self.peek() {
+                    Some("<") => { self.advance(); let rhs = self.parse_additive()?; lhs = (lhs < rhs) as i64; },
+                    Some(">") => { self.advance(); let rhs = self.parse_additive()?; lhs = (lhs > rhs) as i64; },
+                    Some("<=") => { self.advance(); let rhs = self.parse_additive()?; lhs = (lhs <= rhs) as i64; },
+                    Some(">=") => { self.advance(); let rhs = self.parse_additive()?; lhs = (lhs >= rhs) as i64; },
+                    _ => break
+                }
+            }
+
+            Ok(lhs)
+        }
+
+        fn parse_additive(&mut self) -> Result<i64, Error> {
+            let mut lhs = self.parse_multiplicative()?;
+
+            loop {
+                match self.peek() {
+                    Some("+") => {
+                        self.advance();
+                        let rhs = self.parse_multiplicative()?;
+                        lhs = lhs.checked_add(rhs).ok_or_else(Self::overflow_error)?;
+                    },
+                    Some("-") => {
+                        self.advance();
+                        let rhs = self.parse_multiplicative()?;
+                        lhs = lhs.checked_sub(rhs).ok_or_else(Self::overflow_error)?;
+                    },
+                    _ => break
+                }
+            }
+
+            Ok(lhs)
+        }
+
+        fn parse_multiplicative(&mut self) -> Result<i64, Error> {
+            let mut lhs = self.parse_unary()?;
+
+            loop {
+                match self.peek() {
+                    Some("*") => {
+                        self.advance();
+                        let rhs = self.parse_unary()?;
+                        lhs = lhs.checked_mul(rhs).ok_or_else(Self::overflow_error)?;
+                    },
+                    Some("/") => {
+                        self.advance();
+                        let rhs = self.parse_unary()?;
+
+                        if rhs == 0 {
+                            return Err(Error::new(ErrorKind::InvalidData, "Division by zero in preprocessor expression.".to_owned()));
+                        }
+
+                        lhs = lhs.checked_div(rhs).ok_or_else(Self::overflow_error)?;
+                    },
+                    _ => break
+                }
+            }
+
+            Ok(lhs)
+        }
+
+        fn parse_unary(&mut self) -> Result<i64, Error> {
+            if self.peek() == Some("!") {
+                self.advance();
+                let value = self.parse_unary()?;
+                return Ok((value == 0) as i64);
+            }
+
+            if self.peek() == Some("-") {
+                self.advance();
+                let value = self.parse_unary()?;
+                return value.checked_neg().ok_or_else(Self::overflow_error);
             }
 
-            Err(Error::new(ErrorKind::UnexpectedEof, "Expected preprocessor directive `#endif` or `#else`, got EOF."))
+            self.parse_primary()
+        }
+
+        /// Built by every checked arithmetic operation above on overflow, so `i64::MIN / -1` and
+        /// friends surface as a regular `Error` instead of panicking the way the unchecked
+        /// operators they replaced would have.
+        fn overflow_error() -> Error {
+            Error::new(ErrorKind::InvalidData, "Integer overflow in preprocessor expression.".to_owned())
+        }
+
+        fn parse_primary(&mut self) -> Result<i64, Error> {
+            match self.advance() {
+                Some(ref tok) if tok == "(" => {
+                    let value = self.parse_or()?;
+                    self.expect(")")?;
+                    Ok(value)
+                },
+                Some(ref tok) if tok == "defined" => {
+                    let parenthesised = self.peek() == Some("(");
+                    if parenthesised {
+                        self.advance();
+                    }
+
+                    let ident = match self.advance() {
+                        Some(ident) => ident,
+                        None => return Err(Error::new(ErrorKind::InvalidData, "Expected an identifier after `defined`.".to_owned()))
+                    };
+
+                    if parenthesised {
+                        self.expect(")")?;
+                    }
+
+                    Ok(self.defs.contains_key(&ident) as i64)
+                },
+                Some(ref tok) if tok.chars().next().is_some_and(|c| c.is_ascii_digit()) => {
+                    tok.parse::<i64>().map_err(|_| Error::new(ErrorKind::InvalidData, format!("Invalid integer literal `{tok}` in preprocessor expression.")))
+                },
+                Some(ident) => Ok(match self.defs.get(&ident) {
+                    Some(Definition::Object(Some(value))) => value.trim().parse::<i64>().unwrap_or(0),
+                    _ => 0
+                }),
+                None => Err(Error::new(ErrorKind::InvalidData, "Unexpected end of preprocessor expression.".to_owned()))
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn function_macro_does_not_clobber_substring_matches() {
+            let src = "#define F(a) value_a + a\nF(9)";
+            let mut p = Preprocessor::new(src);
+            assert_eq!(p.run().unwrap(), "value_a + 9");
+        }
+
+        #[test]
+        fn function_macro_substitutes_params_simultaneously() {
+            let src = "#define ADD(a, b) (a)+(b)\nADD(b, 1)";
+            let mut p = Preprocessor::new(src);
+            assert_eq!(p.run().unwrap(), "(b)+(1)");
+        }
+
+        #[test]
+        fn expr_evaluator_respects_operator_precedence_and_parens() {
+            let defs = HashMap::new();
+            assert_eq!(ExprEvaluator::eval(&defs, "1 + 2 * 3").unwrap(), 7);
+            assert_eq!(ExprEvaluator::eval(&defs, "(1 + 2) * 3").unwrap(), 9);
+            assert_eq!(ExprEvaluator::eval(&defs, "!0 && (1 == 1)").unwrap(), 1);
+            assert_eq!(ExprEvaluator::eval(&defs, "2 > 1 || 0").unwrap(), 1);
+        }
+
+        #[test]
+        fn expr_evaluator_handles_defined() {
+            let mut defs = HashMap::new();
+            defs.insert("FOO".to_owned(), Definition::Object(None));
+
+            assert_eq!(ExprEvaluator::eval(&defs, "defined(FOO)").unwrap(), 1);
+            assert_eq!(ExprEvaluator::eval(&defs, "defined BAR").unwrap(), 0);
+        }
+
+        #[test]
+        fn expr_evaluator_errors_on_division_by_zero() {
+            let defs = HashMap::new();
+            assert!(ExprEvaluator::eval(&defs, "1 / 0").is_err());
+        }
+
+        #[test]
+        fn expr_evaluator_errors_on_overflow_instead_of_panicking() {
+            let defs = HashMap::new();
+            assert!(ExprEvaluator::eval(&defs, "9223372036854775807 + 1").is_err());
+            assert!(ExprEvaluator::eval(&defs, "(0 - 9223372036854775807 - 1) / -1").is_err());
+        }
+
+        #[test]
+        fn stray_endif_is_an_error() {
+            let mut p = Preprocessor::new("#endif\n");
+            assert!(p.run().is_err());
+        }
+
+        #[test]
+        fn double_else_is_an_error() {
+            let mut p = Preprocessor::new("#ifdef X\n#else\n#else\n#endif\n");
+            assert!(p.run().is_err());
+        }
+
+        #[test]
+        fn elif_after_else_is_an_error() {
+            let mut p = Preprocessor::new("#ifdef X\n#else\n#elif 1\n#endif\n");
+            assert!(p.run().is_err());
+        }
+
+        #[test]
+        fn if_and_elif_expressions_are_not_evaluated_in_a_dead_branch() {
+            let mut p = Preprocessor::new("#ifdef NOPE\n#if 1/0\nfoo\n#endif\n#endif\n");
+            assert_eq!(p.run().unwrap(), "");
+
+            let mut p = Preprocessor::new("#ifdef NOPE\n#if 0\nfoo\n#elif 1/0\nbar\n#endif\n#endif\n");
+            assert_eq!(p.run().unwrap(), "");
+        }
+
+        /// A fresh scratch directory under the system temp dir for a `#with`-file test to write
+        /// into, named after `label` (plus the process id) so concurrently-running tests don't
+        /// collide with one another.
+        fn temp_dir(label: &str) -> PathBuf {
+            let dir = std::env::temp_dir().join(format!("rhl_rs_test_{}_{label}", std::process::id()));
+            fs::create_dir_all(&dir).unwrap();
+            dir
+        }
+
+        #[test]
+        fn with_detects_circular_include() {
+            let dir = temp_dir("circular");
+            let a = dir.join("a.rhl");
+            let b = dir.join("b.rhl");
+            fs::write(&a, "#with b.rhl\n").unwrap();
+            fs::write(&b, "#with a.rhl\n").unwrap();
+
+            let src = fs::read_to_string(&a).unwrap();
+            let mut p = Preprocessor::from_path(&a, &src).unwrap();
+            assert!(p.run().is_err());
+
+            fs::remove_dir_all(&dir).ok();
+        }
+
+        #[test]
+        fn with_dedups_a_diamond_shaped_include() {
+            let dir = temp_dir("diamond");
+            let shared = dir.join("shared.rhl");
+            let a = dir.join("a.rhl");
+            let root = dir.join("root.rhl");
+            fs::write(&shared, "SHARED\n").unwrap();
+            fs::write(&a, "#with shared.rhl\n").unwrap();
+            fs::write(&root, "#with a.rhl\n#with shared.rhl\n").unwrap();
+
+            let src = fs::read_to_string(&root).unwrap();
+            let mut p = Preprocessor::from_path(&root, &src).unwrap();
+            let out = p.run().unwrap();
+
+            assert_eq!(out.matches("SHARED").count(), 1);
+
+            fs::remove_dir_all(&dir).ok();
+        }
+
+        #[test]
+        fn plugin_splices_its_stdout_in_place_of_the_block() {
+            let mut p = Preprocessor::new("#plugin echo\nhello\n#endplugin\n");
+            p.register_plugin("echo".to_owned(), "cat".to_owned());
+
+            let out = p.run().unwrap();
+            assert_eq!(out, "{\"source\":\"hello\",\"defines\":{}}");
+        }
+
+        #[test]
+        fn plugin_with_nonzero_exit_is_an_error() {
+            let mut p = Preprocessor::new("#plugin fail\nhello\n#endplugin\n");
+            p.register_plugin("fail".to_owned(), "false".to_owned());
+
+            assert!(p.run().is_err());
+        }
+
+        #[test]
+        fn map_offset_resolves_bytes_across_a_with_include() {
+            let dir = temp_dir("map_offset");
+            let included = dir.join("included.rhl");
+            let root = dir.join("root.rhl");
+            fs::write(&included, "INCLUDED\n").unwrap();
+            fs::write(&root, "ROOT\n#with included.rhl\n").unwrap();
+
+            let src = fs::read_to_string(&root).unwrap();
+            let mut p = Preprocessor::from_path(&root, &src).unwrap();
+            let out = p.run().unwrap().to_owned();
+
+            let root_byte = out.find("ROOT").unwrap();
+            let (file, line) = p.map_offset(root_byte).unwrap();
+            assert_eq!(file, root.as_path());
+            assert_eq!(line, 0);
+
+            let included_byte = out.find("INCLUDED").unwrap();
+            let (file, line) = p.map_offset(included_byte).unwrap();
+            assert_eq!(file, included.as_path());
+            assert_eq!(line, 0);
+
+            fs::remove_dir_all(&dir).ok();
         }
     }
 }